@@ -0,0 +1,29 @@
+use crate::core::units::Altitude;
+
+/// Attributes of a site, used to drive [`crate::lem::generator::TerrainGenerator`].
+/// Attributes contains uplift rates, erodibilities, base altitudes, maximum slopes, a hillslope
+/// diffusivity, and the parameters for transport-limited sediment routing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TerrainAttributes {
+    /// The base (initial) altitude of the site.
+    pub base_altitude: Altitude,
+    /// The rate of tectonic uplift per unit time.
+    pub uplift_rate: f64,
+    /// The bedrock erodibility `K_br` used in the stream power law.
+    pub erodibility: f64,
+    /// The maximum slope (as an angle in radians) the site's outgoing edge can sustain before
+    /// it is clamped. `None` disables the check.
+    pub max_slope: Option<f64>,
+    /// The hillslope diffusivity `D` used by the linear diffusion sub-step.
+    pub diffusivity: f64,
+    /// The sediment erodibility `K_sed`, used instead of `erodibility` (`K_br`) for the erosion
+    /// term of the routed sediment flux when transport-limited or hybrid erosion is enabled.
+    /// Deposited sediment is not tracked as a separate layer from bedrock.
+    pub sediment_erodibility: f64,
+    /// The fraction of eroded material that is fines (`F_f`): it is assumed to wash out of the
+    /// system rather than being tracked as routed sediment.
+    pub fraction_fines: f64,
+    /// The settling velocity `V` used to compute deposition from the routed sediment flux
+    /// `Q_s` when transport-limited erosion is enabled.
+    pub settling_velocity: f64,
+}
@@ -1,4 +1,4 @@
-use std::collections::BinaryHeap;
+use std::collections::{BinaryHeap, VecDeque};
 use terrain_graph::edge_attributed_undirected::EdgeAttributedUndirectedGraph;
 
 use crate::core::{
@@ -43,6 +43,38 @@ impl PartialOrd for RidgeElement {
     }
 }
 
+struct FillElement {
+    index: usize,
+    elevation: Elevation,
+    seq: u64,
+}
+
+impl FillElement {
+    fn evaluate(&self) -> (Elevation, u64) {
+        (self.elevation, self.seq)
+    }
+}
+
+impl PartialEq for FillElement {
+    fn eq(&self, other: &Self) -> bool {
+        self.evaluate() == other.evaluate()
+    }
+}
+
+impl Eq for FillElement {}
+
+impl Ord for FillElement {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        other.evaluate().partial_cmp(&self.evaluate()).unwrap()
+    }
+}
+
+impl PartialOrd for FillElement {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 impl StreamTree {
     /// Constructs a stream tree from a given terrain data.
     pub fn construct<S: Site>(
@@ -217,4 +249,240 @@ impl StreamTree {
 
         next
     }
+
+    /// Fills depressions using the priority-flood algorithm (Barnes et al. 2014), as an
+    /// alternative to carving an outlet path with [`Self::remove_lakes_from_stream_tree`].
+    ///
+    /// Rather than flipping `next` pointers, this raises the elevation of depression interiors.
+    /// `epsilon` is added at each hop away from a closed site: with a positive `epsilon` every
+    /// site ends up with a strictly descending path to an outlet, so feeding the result back in
+    /// as `elevations` means [`Self::construct`] will find no lakes at all. Pass `0.0` to fill
+    /// to dead-flat instead; the flat surfaces left then still appear as lakes to
+    /// [`Self::construct`], which carves them as usual.
+    pub fn fill_depressions(
+        elevations: &[Elevation],
+        graph: &EdgeAttributedUndirectedGraph<Length>,
+        outlets: &[usize],
+        epsilon: Elevation,
+    ) -> Vec<Elevation> {
+        let num = elevations.len();
+        let mut filled = elevations.to_owned();
+        let mut closed = vec![false; num];
+        let mut heap: BinaryHeap<FillElement> = BinaryHeap::with_capacity(num);
+        let mut seq: u64 = 0;
+
+        outlets.iter().for_each(|&i| {
+            closed[i] = true;
+            heap.push(FillElement {
+                index: i,
+                elevation: filled[i],
+                seq,
+            });
+            seq += 1;
+        });
+
+        while let Some(element) = heap.pop() {
+            let i = element.index;
+            graph.neighbors_of(i).iter().for_each(|ja| {
+                let j = ja.0;
+                if closed[j] {
+                    return;
+                }
+
+                filled[j] = filled[j].max(element.elevation + epsilon);
+                closed[j] = true;
+                heap.push(FillElement {
+                    index: j,
+                    elevation: filled[j],
+                    seq,
+                });
+                seq += 1;
+            });
+        }
+
+        filled
+    }
+
+    /// Computes the Strahler stream order of every site from the constructed `next` pointers.
+    ///
+    /// A leaf (no inflows) has order 1; a site whose inflows share the same highest order
+    /// across two or more of them gets that order plus one, otherwise it inherits the highest
+    /// inflow order unchanged. Sites are resolved in upstream-to-downstream order by repeatedly
+    /// releasing sites whose inflows have all already been resolved.
+    pub fn stream_order(&self) -> Vec<u32> {
+        let num = self.next.len();
+
+        // `inflows[j]` lists the sites that flow directly into `j`.
+        let mut inflows: Vec<Vec<usize>> = vec![Vec::new(); num];
+        (0..num).for_each(|i| {
+            let j = self.next[i];
+            if j != i {
+                inflows[j].push(i);
+            }
+        });
+
+        let mut remaining: Vec<usize> = inflows.iter().map(|v| v.len()).collect();
+        let mut max_inflow_order = vec![0u32; num];
+        let mut count_at_max = vec![0u32; num];
+        let mut order = vec![0u32; num];
+
+        let mut queue: VecDeque<usize> = (0..num).filter(|&i| remaining[i] == 0).collect();
+        while let Some(i) = queue.pop_front() {
+            order[i] = if inflows[i].is_empty() {
+                1
+            } else if count_at_max[i] >= 2 {
+                max_inflow_order[i] + 1
+            } else {
+                max_inflow_order[i]
+            };
+
+            let j = self.next[i];
+            if j != i {
+                if order[i] > max_inflow_order[j] {
+                    max_inflow_order[j] = order[i];
+                    count_at_max[j] = 1;
+                } else if order[i] == max_inflow_order[j] {
+                    count_at_max[j] += 1;
+                }
+
+                remaining[j] -= 1;
+                if remaining[j] == 0 {
+                    queue.push_back(j);
+                }
+            }
+        }
+
+        order
+    }
+
+    /// Buckets sites by a size metric (Strahler order, drainage area, ...) against
+    /// user-supplied ascending thresholds, e.g. to classify channels into
+    /// brook/stream/river/major-river bands. Returns, for each site, the number of thresholds
+    /// it meets or exceeds (`0` falls below every threshold).
+    pub fn classify_by_threshold(values: &[f64], thresholds: &[f64]) -> Vec<usize> {
+        values
+            .iter()
+            .map(|&value| {
+                thresholds
+                    .iter()
+                    .filter(|&&threshold| value >= threshold)
+                    .count()
+            })
+            .collect()
+    }
+}
+
+/// The default Freeman (1991) exponent used to weight multiple-flow-direction accumulation.
+pub const DEFAULT_FREEMAN_EXPONENT: f64 = 1.1;
+
+/// Accumulates drainage area with multiple-flow-direction (MFD) routing, as an alternative to
+/// the single steepest-descent routing `next` provides.
+///
+/// Each site distributes its accumulated area across every lower neighbor `j`, weighted by
+/// `w_ij ∝ ((h_i − h_j) / L_ij)^freeman_exponent` normalized over all of `i`'s lower neighbors.
+/// Sites are processed in descending-elevation order so that a site's accumulated area is final
+/// before it is distributed onward. `areas` is the base (unaccumulated) area of each site; the
+/// tree-based `next` is unaffected and still used for the erosion response-time integration.
+pub fn accumulate_mfd(
+    elevations: &[Elevation],
+    graph: &EdgeAttributedUndirectedGraph<Length>,
+    areas: &[f64],
+    freeman_exponent: f64,
+) -> Vec<f64> {
+    let num = elevations.len();
+    let mut order: Vec<usize> = (0..num).collect();
+    order.sort_by(|&a, &b| elevations[b].partial_cmp(&elevations[a]).unwrap());
+
+    let mut accumulated = areas.to_owned();
+
+    order.iter().for_each(|&i| {
+        let downhill: Vec<(usize, f64)> = graph
+            .neighbors_of(i)
+            .iter()
+            .filter_map(|ja| {
+                let j = ja.0;
+                if elevations[j] < elevations[i] {
+                    let distance = ja.1;
+                    let slope = (elevations[i] - elevations[j]) / distance;
+                    Some((j, slope.powf(freeman_exponent)))
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        let weight_sum: f64 = downhill.iter().map(|&(_, w)| w).sum();
+        if weight_sum > 0.0 {
+            downhill.iter().for_each(|&(j, w)| {
+                accumulated[j] += accumulated[i] * w / weight_sum;
+            });
+        }
+    });
+
+    accumulated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fill_depressions_raises_a_single_cell_pit_to_its_rim() {
+        // 0 (outlet, elev 0.0) -- 1 (rim, elev 5.0) -- 2 (pit, elev -3.0)
+        let elevations: Vec<Elevation> = vec![0.0, 5.0, -3.0];
+        let mut graph = EdgeAttributedUndirectedGraph::<Length>::new(3);
+        graph.add_edge(0, 1, 1.0);
+        graph.add_edge(1, 2, 1.0);
+
+        let filled = StreamTree::fill_depressions(&elevations, &graph, &[0], 0.0);
+
+        assert_eq!(filled[0], 0.0);
+        assert_eq!(filled[1], 5.0);
+        assert_eq!(filled[2], 5.0, "the pit should be raised up to its rim");
+        assert!(filled[2] >= filled[1] - 1e-9);
+    }
+
+    #[test]
+    fn stream_order_gives_order_2_at_a_v_confluence() {
+        // leaves 0 and 1 join at confluence 2, which drains to outlet 3.
+        let stream_tree = StreamTree {
+            next: vec![2, 2, 3, 3],
+        };
+
+        let order = stream_tree.stream_order();
+
+        assert_eq!(order, vec![1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn classify_by_threshold_buckets_by_value() {
+        let values = vec![1.0, 1.0, 2.0, 2.0];
+        let thresholds = vec![2.0, 3.0];
+
+        assert_eq!(
+            StreamTree::classify_by_threshold(&values, &thresholds),
+            vec![0, 0, 1, 1]
+        );
+    }
+
+    #[test]
+    fn accumulate_mfd_conserves_total_area_at_the_sink() {
+        //      0 (elev 3)
+        //     / \
+        //    1   2  (elev 1, elev 2)
+        //     \ /
+        //      3     (elev 0, the only sink)
+        let elevations: Vec<Elevation> = vec![3.0, 1.0, 2.0, 0.0];
+        let areas = vec![1.0, 1.0, 1.0, 1.0];
+        let mut graph = EdgeAttributedUndirectedGraph::<Length>::new(4);
+        graph.add_edge(0, 1, 1.0);
+        graph.add_edge(0, 2, 1.0);
+        graph.add_edge(1, 3, 1.0);
+        graph.add_edge(2, 3, 1.0);
+
+        let accumulated = accumulate_mfd(&elevations, &graph, &areas, 1.1);
+
+        let total_area: f64 = areas.iter().sum();
+        assert!((accumulated[3] - total_area).abs() < 1e-9);
+    }
 }